@@ -3,18 +3,23 @@ use clang::*;
 use indexmap::IndexMap;
 use itertools::Itertools;
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // NOTE:
 //  Can use BTreeMap to order lexicorigraphically instread
 //    - ashkan, Sun 29 Aug 2021 03:39:04 AM JST
 use IndexMap as StructMap;
 
-#[derive(parse_display::Display, Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone)]
+#[derive(
+    parse_display::Display, Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone, Serialize, Deserialize,
+)]
+#[serde(transparent)]
 struct TypeId(String);
 
 impl Into<TypeId> for Type<'_> {
@@ -23,22 +28,39 @@ impl Into<TypeId> for Type<'_> {
     }
 }
 
-fn underlying_type<'a>(mut ty_: Type<'a>) -> Type<'a> {
+// Strip every pointer level off `ty_`, returning the pointee's canonical type along
+// with how many levels were stripped. The depth tells a field apart from an
+// embedded-by-value dependency: `struct A { struct B b; }` has depth 0 (B's layout is
+// part of A's), `struct A { struct B *b; }` has depth 1 (just a name dependency, safe
+// to form a cycle with B).
+fn underlying_type_and_depth<'a>(mut ty_: Type<'a>) -> (Type<'a>, usize) {
+    let mut depth = 0;
     while let Some(underlying) = ty_.get_pointee_type() {
         ty_ = underlying;
+        depth += 1;
     }
-    ty_.get_canonical_type()
+    (ty_.get_canonical_type(), depth)
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 struct Field {
     name: Option<String>,
     type_id: TypeId,
     offset: Option<usize>,
     underlying: TypeId,
+    // 0 means `underlying` is embedded by value (contributes to this record's layout
+    // and size); >0 means it's reached through that many levels of pointer indirection
+    // (a by-reference, name-only dependency that may legally cycle).
+    pointer_depth: usize,
     bit_field_width: Option<usize>,
 }
 
+impl Field {
+    fn is_by_value(&self) -> bool {
+        self.pointer_depth == 0
+    }
+}
+
 impl std::fmt::Display for Field {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(bit_field_width) = self.bit_field_width {
@@ -57,6 +79,9 @@ impl std::fmt::Display for Field {
                 self.type_id
             )?;
         }
+        if self.pointer_depth > 0 {
+            write!(f, " (ptr depth {})", self.pointer_depth)?;
+        }
         if let Some(offset) = self.offset {
             write!(f, " @ {}", offset)?;
         }
@@ -64,15 +89,28 @@ impl std::fmt::Display for Field {
     }
 }
 
-#[derive(parse_display::Display, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(
+    parse_display::Display,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Serialize,
+    Deserialize,
+)]
 #[display(style = "snake_case")]
+#[serde(rename_all = "snake_case")]
 enum RecordKind {
     Struct,
     Union,
     Enum,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 struct RecordInfo {
     kind: RecordKind,
     aliases: BTreeSet<String>,
@@ -123,18 +161,44 @@ fn find_record_def<'a>(node: Entity<'a>) -> Option<(Entity<'a>, String)> {
     None
 }
 
-fn main() -> Result<()> {
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "warn");
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mode {
+    Print,
+    Hash,
+    Diff,
+    Bindgen,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Either re-parse `path` as a C header, or, if it looks like a snapshot dumped via
+// `--format json`, load it directly so `diff` can run against a stored snapshot.
+fn load_lookup(
+    path: PathBuf,
+    name_filters: &HashSet<String>,
+) -> Result<StructMap<TypeId, RecordInfo>> {
+    if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+        load_snapshot(&path)
+    } else {
+        analyze(path, name_filters).map(|(struct_lookup, _)| struct_lookup)
     }
-    env_logger::init();
-    let mut it = std::env::args();
-    it.next().ok_or_else(|| anyhow!("Need arg"))?;
-    let file: PathBuf = it.next().ok_or_else(|| anyhow!("Need arg"))?.parse()?;
-    info!("{}", file.display());
-    let name_filters: HashSet<_> = it.collect();
-    ensure!(!name_filters.is_empty(), "Need a name filter");
-    info!("{:?}", name_filters);
+}
+
+// Parse `file`, collect a `RecordInfo` for every record it defines, and return that
+// lookup together with the closure reachable from `name_filters`, toposorted
+// (dependencies before dependents). Shared by every mode so `diff` can run
+// this twice, once per snapshot, with identical filters. The returned closure may be
+// empty if none of `name_filters` appear in `file` at all -- callers that need at least
+// one match (print/hash/bindgen) must check for that themselves; `diff` deliberately
+// doesn't, since "absent from this snapshot" is itself a reportable diff.
+fn analyze(
+    file: PathBuf,
+    name_filters: &HashSet<String>,
+) -> Result<(StructMap<TypeId, RecordInfo>, Vec<TypeId>)> {
     let clang = Clang::new().map_err(|v| anyhow!("{}", v))?;
     let index = Index::new(&clang, false, false);
     let tu = index.parser(file).parse()?;
@@ -180,6 +244,8 @@ fn main() -> Result<()> {
                             .map(|child| {
                                 let name = child.get_name();
                                 let type_ = child.get_type().unwrap();
+                                let (underlying, pointer_depth) =
+                                    underlying_type_and_depth(type_);
                                 Field {
                                     offset: name
                                         .as_ref()
@@ -190,7 +256,8 @@ fn main() -> Result<()> {
                                                 .map(|(_s, u)| u as usize)
                                         }),
                                     type_id: type_.into(),
-                                    underlying: underlying_type(type_).into(),
+                                    underlying: underlying.into(),
+                                    pointer_depth,
                                     name,
                                     bit_field_width: child.get_bit_field_width(),
                                 }
@@ -219,12 +286,7 @@ fn main() -> Result<()> {
     let mut toposorted = Vec::new();
     let mut visited = HashSet::new();
     let mut processed = HashSet::new();
-    let mut discovered = HashSet::new();
-    let mut stack: Vec<TypeId> = vec![];
-    for target in targets {
-        stack.push(target.clone());
-        discovered.insert(target);
-    }
+    let mut stack: Vec<TypeId> = targets.into_iter().collect();
     while let Some(type_id) = stack.pop() {
         // Mark
         if visited.insert(type_id.clone()) {
@@ -234,10 +296,33 @@ fn main() -> Result<()> {
 
             stack.push(type_id.clone());
 
-            // Discover
+            // Discover: only by-value fields contribute to the layout ordering. A
+            // by-reference (pointer) field is just a name dependency and is allowed to
+            // cycle (e.g. `struct node { struct node *next; }`), so it never joins the
+            // stack here.
+            //
+            // Gate the push on `visited`, not on some separate "already discovered"
+            // set: two siblings can both embed the same by-value dependency before
+            // either of them is popped (e.g. `struct A { struct C c; struct B b; };
+            // struct B { struct C c; };`), so the dependency must still be re-pushed
+            // on the second sighting -- it isn't done being processed yet just because
+            // something already put it on the stack once. Pushing it again is safe:
+            // the stack's LIFO order guarantees that copy gets fully processed before
+            // any earlier (deeper) copy is popped, and the `processed` check below
+            // keeps a dependency from being emitted into `toposorted` more than once.
             for field in node.fields.iter() {
+                if !field.is_by_value() {
+                    continue;
+                }
                 if struct_lookup.contains_key(&field.underlying) {
-                    if discovered.insert(field.underlying.clone()) {
+                    ensure!(
+                        !visited.contains(&field.underlying) || processed.contains(&field.underlying),
+                        "Layout cycle detected: {} embeds {} by value, which (transitively) embeds {} by value",
+                        type_id,
+                        field.underlying,
+                        type_id,
+                    );
+                    if !visited.contains(&field.underlying) {
                         stack.push(field.underlying.clone());
                     }
                 }
@@ -246,36 +331,582 @@ fn main() -> Result<()> {
             toposorted.push(type_id);
         }
     }
+    Ok((struct_lookup, toposorted))
+}
+
+// Map a clang canonical base type (already stripped of pointers, see
+// `underlying_type_and_depth`) to a Rust type. This only needs to cover what actually
+// shows up in C headers, not the full C type grammar.
+fn c_base_type_to_rust(type_id: &TypeId) -> String {
+    match type_id.0.trim() {
+        "void" => "std::ffi::c_void".to_string(),
+        "char" => "std::ffi::c_char".to_string(),
+        "signed char" => "i8".to_string(),
+        "unsigned char" => "u8".to_string(),
+        "short" | "short int" => "i16".to_string(),
+        "unsigned short" | "unsigned short int" => "u16".to_string(),
+        "int" => "i32".to_string(),
+        "unsigned int" | "unsigned" => "u32".to_string(),
+        "long" | "long int" => "i64".to_string(),
+        "unsigned long" | "unsigned long int" => "u64".to_string(),
+        "long long" | "long long int" => "i64".to_string(),
+        "unsigned long long" | "unsigned long long int" => "u64".to_string(),
+        "float" => "f32".to_string(),
+        "double" => "f64".to_string(),
+        "_Bool" | "bool" => "bool".to_string(),
+        other => other
+            .trim_start_matches("struct ")
+            .trim_start_matches("union ")
+            .trim_start_matches("enum ")
+            .to_string(),
+    }
+}
+
+// The name a record is emitted under: its first (lexicographically) alias, same as
+// what `generate_bindings` names the record's own definition with. Any reference to a
+// record must go through this instead of stripping `struct `/`union `/`enum ` off its
+// `TypeId`, since a typedef'd record's chosen alias and its C tag are often different
+// names (`typedef struct _foo {...} Foo;` picks `Foo`, not `_foo`).
+fn record_name(record: &RecordInfo) -> String {
+    record
+        .aliases
+        .iter()
+        .next()
+        .cloned()
+        .unwrap_or_else(|| record.type_id.0.clone())
+}
+
+// Map a `Field` to its Rust type, re-wrapping `pointer_depth` levels of `*mut` around
+// the dereferenced base type. Fields whose `underlying` is itself one of our records
+// are named the same way that record's own definition is, so the reference resolves.
+fn field_type_to_rust(field: &Field, struct_lookup: &StructMap<TypeId, RecordInfo>) -> String {
+    let mut rust = match struct_lookup.get(&field.underlying) {
+        Some(record) => record_name(record),
+        None => c_base_type_to_rust(&field.underlying),
+    };
+    for _ in 0..field.pointer_depth {
+        rust = format!("*mut {}", rust);
+    }
+    rust
+}
+
+// The smallest unsigned integer that can hold a bit-field of the given width, i.e. its
+// packed backing member. A plain `Field.bit_field_width`-wide declaration of the
+// *declared* type (e.g. `i32` for `int x : 3`) would make the struct wider than the C
+// layout it's supposed to match.
+fn bit_field_backing_type(width: usize) -> &'static str {
+    match width {
+        0..=8 => "u8",
+        9..=16 => "u16",
+        17..=32 => "u32",
+        _ => "u64",
+    }
+}
+
+// The signed Rust integer matching a C enum's underlying storage size, as reported by
+// `RecordInfo.size`.
+fn enum_backing_type(size: usize) -> &'static str {
+    match size {
+        1 => "i8",
+        2 => "i16",
+        4 => "i32",
+        8 => "i64",
+        _ => "i32",
+    }
+}
+
+// `Field.offset` stores an enum constant's value as the unsigned bit pattern clang
+// reported it in (see `get_enum_constant_value` in `analyze`), truncated to `usize`.
+// Reinterpret those bits as a signed integer of the enum's actual storage width so a
+// negative C enumerator round-trips instead of printing as a huge positive literal
+// that overflows its backing type.
+fn enum_discriminant(raw: usize, size: usize) -> i64 {
+    let bits = (size.min(8) * 8) as u32;
+    if bits == 0 || bits >= 64 {
+        return raw as i64;
+    }
+    let mask = (1u64 << bits) - 1;
+    let value = (raw as u64) & mask;
+    let sign_bit = 1u64 << (bits - 1);
+    if value & sign_bit != 0 {
+        (value as i64) - (1i64 << bits)
+    } else {
+        value as i64
+    }
+}
+
+// Turn the toposorted closure into compilable `#[repr(C)]` Rust, emitted in dependency
+// order so forward references resolve, with an `offset_of!` assertion per named field
+// and a `size_of` assertion per record so a layout mismatch between the header and the
+// generated bindings fails to compile instead of silently corrupting memory at runtime.
+// Records with bit-fields get no `size_of` assertion and no `offset_of!` assertions
+// from their first bit-field onward (see below).
+fn generate_bindings(struct_lookup: &StructMap<TypeId, RecordInfo>, toposorted: &[TypeId]) -> String {
+    use std::fmt::Write;
+    let mut out = String::new();
+
+    // Types reached only through a pointer field never appear in `toposorted` --
+    // chunk0-5 deliberately excludes by-reference edges from the layout ordering, since
+    // they're just a name dependency -- but `field_type_to_rust` still names them for
+    // any pointer field whose pointee is in `struct_lookup`. Without a declaration here
+    // the generated bindings wouldn't compile, so forward-declare each such pointee as
+    // opaque: we don't know its fields, only that something points to it.
+    let by_value: HashSet<&TypeId> = toposorted.iter().collect();
+    let mut forward_declared = HashSet::new();
+    for type_id in toposorted {
+        let record = &struct_lookup[type_id];
+        for field in &record.fields {
+            if field.is_by_value() || by_value.contains(&field.underlying) {
+                continue;
+            }
+            let Some(pointee) = struct_lookup.get(&field.underlying) else {
+                continue;
+            };
+            if !forward_declared.insert(pointee.type_id.clone()) {
+                continue;
+            }
+            let keyword = if pointee.kind == RecordKind::Union {
+                "union"
+            } else {
+                "struct"
+            };
+            writeln!(out, "#[repr(C)]").unwrap();
+            writeln!(
+                out,
+                "pub {} {} {{ _opaque: [u8; 0] }} // forward declaration, only reached by pointer",
+                keyword,
+                record_name(pointee)
+            )
+            .unwrap();
+        }
+    }
+    if !forward_declared.is_empty() {
+        writeln!(out).unwrap();
+    }
+
+    for type_id in toposorted {
+        let record = &struct_lookup[type_id];
+        let name = record_name(record);
+        match record.kind {
+            RecordKind::Enum => {
+                // C enumerators can repeat values and go negative; a native Rust
+                // `enum` rejects both (duplicate or out-of-range discriminants are a
+                // hard compile error), so represent it as a transparent newtype with
+                // one associated const per variant instead.
+                let backing = enum_backing_type(record.size);
+                writeln!(out, "#[repr(transparent)]").unwrap();
+                writeln!(out, "#[derive(Clone, Copy, PartialEq, Eq, Debug)]").unwrap();
+                writeln!(out, "pub struct {}(pub {});", name, backing).unwrap();
+                writeln!(out, "impl {} {{", name).unwrap();
+                for field in &record.fields {
+                    let variant = field.name.as_deref().unwrap_or("_");
+                    let value = enum_discriminant(field.offset.unwrap_or(0), record.size);
+                    writeln!(
+                        out,
+                        "    pub const {}: {} = {}({});",
+                        variant, name, name, value
+                    )
+                    .unwrap();
+                }
+                writeln!(out, "}}").unwrap();
+                writeln!(
+                    out,
+                    "const _: () = assert!(core::mem::size_of::<{}>() == {});\n",
+                    name, record.size
+                )
+                .unwrap();
+            }
+            RecordKind::Struct | RecordKind::Union => {
+                let keyword = if record.kind == RecordKind::Union {
+                    "union"
+                } else {
+                    "struct"
+                };
+                writeln!(out, "#[repr(C)]").unwrap();
+                writeln!(out, "pub {} {} {{", keyword, name).unwrap();
+                for (i, field) in record.fields.iter().enumerate() {
+                    let field_name = field
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("_unnamed{}", i));
+                    match field.bit_field_width {
+                        Some(width) => {
+                            // Pack into the narrowest backing integer instead of the
+                            // declared type, since a full-width member here would throw
+                            // off the struct's overall size relative to C's packed
+                            // layout.
+                            let backing = bit_field_backing_type(width);
+                            writeln!(
+                                out,
+                                "    pub {}: {}, // bit-field, width {}, packed into {}",
+                                field_name, backing, width, backing
+                            )
+                            .unwrap();
+                        }
+                        None => {
+                            let rust_ty = field_type_to_rust(field, struct_lookup);
+                            writeln!(out, "    pub {}: {},", field_name, rust_ty).unwrap();
+                        }
+                    }
+                }
+                writeln!(out, "}}").unwrap();
+                let has_bit_fields = record.fields.iter().any(|f| f.bit_field_width.is_some());
+                // Each bit-field above is packed into its own whole backing integer
+                // rather than sharing storage the way C packs adjacent bit-fields, so
+                // every field's Rust offset diverges from its C offset from the first
+                // bit-field onward. Only the fields before that point still land where
+                // the assertion says; stop emitting offset asserts once we pass one.
+                let mut seen_bit_field = false;
+                for field in &record.fields {
+                    if field.bit_field_width.is_some() {
+                        seen_bit_field = true;
+                        continue;
+                    }
+                    if seen_bit_field {
+                        continue;
+                    }
+                    if let (Some(field_name), Some(offset)) = (&field.name, field.offset) {
+                        writeln!(
+                            out,
+                            "const _: () = assert!(core::mem::offset_of!({}, {}) == {});",
+                            name, field_name, offset
+                        )
+                        .unwrap();
+                    }
+                }
+                // A record with bit-fields doesn't actually land at `record.size` once
+                // packed into whole backing integers above (C packs to the bit, Rust
+                // can't) -- emitting the assertion there would just fail to compile.
+                if !has_bit_fields {
+                    writeln!(
+                        out,
+                        "const _: () = assert!(core::mem::size_of::<{}>() == {});\n",
+                        name, record.size
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+// Load a `struct_lookup` previously dumped with `--format json`, so `diff` can compare
+// against a stored snapshot instead of re-parsing a header.
+fn load_snapshot(path: &Path) -> Result<StructMap<TypeId, RecordInfo>> {
+    let data = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+// Every type reachable from `name_filters` by following by-value field embeddings
+// (mirroring the closure `analyze`'s toposort builds), not just the filters
+// themselves -- so a layout change nested inside a non-filter type gets attributed to
+// that type specifically instead of showing up as just an offset shift on whatever
+// embeds it.
+fn reachable_type_ids(
+    lookup: &StructMap<TypeId, RecordInfo>,
+    name_filters: &HashSet<String>,
+) -> BTreeSet<TypeId> {
+    let mut discovered = BTreeSet::new();
+    let mut stack: Vec<TypeId> = lookup
+        .values()
+        .filter(|record| record.aliases.iter().any(|alias| name_filters.contains(alias)))
+        .map(|record| record.type_id.clone())
+        .collect();
+    while let Some(type_id) = stack.pop() {
+        if !discovered.insert(type_id.clone()) {
+            continue;
+        }
+        if let Some(record) = lookup.get(&type_id) {
+            for field in &record.fields {
+                if field.is_by_value() && lookup.contains_key(&field.underlying) {
+                    stack.push(field.underlying.clone());
+                }
+            }
+        }
+    }
+    discovered
+}
+
+// Compare two snapshots of the same set of types and report exactly what changed per
+// field, rather than just a boolean mismatch. Types matched by alias are diffed field
+// by field; a type present in only one snapshot is reported wholly added/removed.
+fn diff_records(
+    old_lookup: &StructMap<TypeId, RecordInfo>,
+    new_lookup: &StructMap<TypeId, RecordInfo>,
+    name_filters: &HashSet<String>,
+) {
+    let find_by_alias = |lookup: &StructMap<TypeId, RecordInfo>, alias: &str| {
+        lookup.values().find(|record| record.aliases.contains(alias))
+    };
+
+    // The filter names plus the representative name of everything reachable from them
+    // in either snapshot, so nested by-value dependencies get their own report too.
+    let mut aliases: BTreeSet<String> = name_filters.iter().cloned().collect();
+    for type_id in reachable_type_ids(old_lookup, name_filters)
+        .iter()
+        .chain(reachable_type_ids(new_lookup, name_filters).iter())
+    {
+        if let Some(record) = old_lookup.get(type_id).or_else(|| new_lookup.get(type_id)) {
+            aliases.insert(record_name(record));
+        }
+    }
+
+    for alias in &aliases {
+        let old = find_by_alias(old_lookup, alias);
+        let new = find_by_alias(new_lookup, alias);
+        match (old, new) {
+            (None, None) => println!("{}: not found in either snapshot", alias),
+            (Some(_), None) => println!("{}: removed", alias),
+            (None, Some(_)) => println!("{}: added", alias),
+            (Some(old), Some(new)) => diff_record(alias, old, new),
+        }
+    }
+}
+
+fn diff_record(alias: &str, old: &RecordInfo, new: &RecordInfo) {
+    let mut changed = false;
+    let mut note = |line: String| {
+        if !changed {
+            println!("{}:", alias);
+            changed = true;
+        }
+        println!("  {}", line);
+    };
+    if old.kind != new.kind {
+        note(format!("kind: {} -> {}", old.kind, new.kind));
+    }
+    if old.size != new.size {
+        note(format!("size: {} -> {}", old.size, new.size));
+    }
+    // Key by declaration index when a field has no name (anonymous union/struct
+    // members) instead of dropping it -- otherwise an added/removed anonymous member
+    // is simply invisible in the report.
+    let field_key = |i: usize, f: &Field| f.name.clone().unwrap_or_else(|| format!("_unnamed{}", i));
+    let old_fields: IndexMap<_, _> = old
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (field_key(i, f), f))
+        .collect();
+    let new_fields: IndexMap<_, _> = new
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (field_key(i, f), f))
+        .collect();
+    for (name, old_field) in &old_fields {
+        match new_fields.get(name) {
+            None => note(format!("- {}", old_field)),
+            Some(new_field) if **new_field != **old_field => {
+                note(format!("- {}", old_field));
+                note(format!("+ {}", new_field));
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, new_field) in &new_fields {
+        if !old_fields.contains_key(name) {
+            note(format!("+ {}", new_field));
+        }
+    }
+}
+
+// A field's dependency as fed into its owner's hash: the dependency's own hash once
+// one has been computed for it, or just its name otherwise (pointer fields, or a
+// dependency outside the closure we were asked about).
+#[derive(Hash)]
+enum DepHash {
+    Known(u64),
+    Unknown(String),
+}
+
+// Process types in `toposorted` order (dependencies before dependents) and fold each
+// `RecordInfo` into a single `u64`. A field's `underlying` is hashed via the
+// already-computed hash of that dependency when one exists, so a layout change in a
+// nested struct propagates upward into everything that embeds it. Fields reached
+// through a pointer hash only the `TypeId` string instead, since recursing through
+// them would turn self-referential structs like `struct node { struct node *next; }`
+// into an infinite hash.
+fn compute_hashes(
+    struct_lookup: &StructMap<TypeId, RecordInfo>,
+    toposorted: &[TypeId],
+) -> HashMap<TypeId, u64> {
+    let mut hashes = HashMap::new();
+    for type_id in toposorted {
+        let record = &struct_lookup[type_id];
+        // `record.fields` is in declaration order; sort by offset then name so the hash
+        // doesn't depend on that order.
+        let mut fields: Vec<&Field> = record.fields.iter().collect();
+        fields.sort_by(|a, b| a.offset.cmp(&b.offset).then_with(|| a.name.cmp(&b.name)));
+        let fields_repr: Vec<_> = fields
+            .into_iter()
+            .map(|field| {
+                let dep = match hashes.get(&field.underlying) {
+                    Some(dep_hash) if field.is_by_value() => DepHash::Known(*dep_hash),
+                    None if field.is_by_value() && struct_lookup.contains_key(&field.underlying) => {
+                        // `toposorted` guarantees every by-value dependency is hashed
+                        // before its dependents; if that ever regresses we want a loud
+                        // failure here, not a silently wrong hash that just shrugs off a
+                        // real ABI change as `Unknown`.
+                        unreachable!(
+                            "{} embeds {} by value but {} wasn't hashed first",
+                            type_id, field.underlying, field.underlying
+                        )
+                    }
+                    _ => DepHash::Unknown(field.underlying.0.clone()),
+                };
+                (field.name.clone(), field.bit_field_width, field.offset, dep)
+            })
+            .collect();
+        let digest_input = (record.kind, record.size, fields_repr);
+        // Use the noisy byte-by-byte hasher when debug logging is on, so a `-D
+        // RUST_LOG=debug` run shows exactly what went into each type's hash.
+        let digest = if log_enabled!(log::Level::Debug) {
+            debug_hash(&digest_input, &record.type_id)
+        } else {
+            hash(&digest_input)
+        };
+        hashes.insert(type_id.clone(), digest);
+    }
+    hashes
+}
+
+fn main() -> Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "warn");
+    }
+    env_logger::init();
+
+    // Pull `--format <text|json>` out of the args wherever it appears; everything else
+    // stays positional (mode, file(s), name filters).
+    let mut format = OutputFormat::Text;
+    let mut positional = Vec::new();
+    let mut raw_args = std::env::args();
+    raw_args.next().ok_or_else(|| anyhow!("Need arg"))?;
+    while let Some(arg) = raw_args.next() {
+        if arg == "--format" {
+            let value = raw_args.next().ok_or_else(|| anyhow!("--format needs a value"))?;
+            format = match value.as_str() {
+                "json" => OutputFormat::Json,
+                "text" => OutputFormat::Text,
+                other => bail!("Unknown format {:?}, expected json or text", other),
+            };
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut it = positional.into_iter();
+
+    let mut arg = it.next().ok_or_else(|| anyhow!("Need arg"))?;
+    let mode = match arg.as_str() {
+        "hash" => {
+            arg = it.next().ok_or_else(|| anyhow!("Need arg"))?;
+            Mode::Hash
+        }
+        "diff" => {
+            arg = it.next().ok_or_else(|| anyhow!("Need arg"))?;
+            Mode::Diff
+        }
+        "bindgen" => {
+            arg = it.next().ok_or_else(|| anyhow!("Need arg"))?;
+            Mode::Bindgen
+        }
+        "print" => {
+            arg = it.next().ok_or_else(|| anyhow!("Need arg"))?;
+            Mode::Print
+        }
+        _ => Mode::Print,
+    };
+    // `--format json` only changes what `print` dumps: the whole record lookup (every
+    // record in the file, usable as a `diff` snapshot) instead of the toposorted
+    // closure that text output prints. The other modes have
+    // their own fixed output shape, so silently ignoring the flag there would be
+    // misleading.
+    ensure!(
+        format == OutputFormat::Text || mode == Mode::Print,
+        "--format json is only supported in print mode"
+    );
+
+    if mode == Mode::Diff {
+        let old_file: PathBuf = arg.parse()?;
+        let new_file: PathBuf = it.next().ok_or_else(|| anyhow!("Need arg"))?.parse()?;
+        let name_filters: HashSet<_> = it.collect();
+        ensure!(!name_filters.is_empty(), "Need a name filter");
+        info!("{:?}", name_filters);
+        let old_lookup = load_lookup(old_file, &name_filters)?;
+        let new_lookup = load_lookup(new_file, &name_filters)?;
+        diff_records(&old_lookup, &new_lookup, &name_filters);
+        return Ok(());
+    }
+
+    let file: PathBuf = arg.parse()?;
+    info!("{}", file.display());
+    let name_filters: HashSet<_> = it.collect();
+    ensure!(!name_filters.is_empty(), "Need a name filter");
+    info!("{:?}", name_filters);
+    let (struct_lookup, toposorted) = analyze(file, &name_filters)?;
     ensure!(!toposorted.is_empty(), "Failed to find any names");
-    for dep in toposorted {
-        println!("{}", struct_lookup[&dep]);
+
+    match mode {
+        Mode::Print if format == OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&struct_lookup)?);
+        }
+        Mode::Print => {
+            for dep in toposorted {
+                println!("{}", struct_lookup[&dep]);
+            }
+        }
+        Mode::Hash => {
+            let hashes = compute_hashes(&struct_lookup, &toposorted);
+            for record in struct_lookup.values() {
+                let hash = match hashes.get(&record.type_id) {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                for alias in &record.aliases {
+                    if name_filters.contains(alias) {
+                        println!("{} => {:016x}", alias, hash);
+                    }
+                }
+            }
+        }
+        Mode::Bindgen => {
+            print!("{}", generate_bindings(&struct_lookup, &toposorted));
+        }
+        Mode::Diff => unreachable!("handled above"),
     }
     Ok(())
 }
 
+// Traces every byte fed into a hash to stderr, but must still produce the same digest
+// a plain `DefaultHasher` would -- the value this computes feeds into dependents'
+// hashes, so a `RUST_LOG=debug` run can't be allowed to change what the stored ABI
+// hash actually is.
 struct DebugHasher {
     label: String,
-    count: u64,
+    inner: DefaultHasher,
 }
 
 impl DebugHasher {
     fn new(label: impl std::fmt::Display) -> Self {
         Self {
             label: label.to_string(),
-            count: 0,
+            inner: DefaultHasher::new(),
         }
     }
 }
 
 impl Hasher for DebugHasher {
     fn write(&mut self, bytes: &[u8]) {
-        self.count += 1;
         eprintln!("Hash({}) {:?}", self.label, bytes);
+        self.inner.write(bytes);
     }
 
     fn finish(&self) -> u64 {
-        eprintln!("Hash({}) Finished", self.label);
-        self.count
+        let digest = self.inner.finish();
+        eprintln!("Hash({}) Finished -> {:016x}", self.label, digest);
+        digest
     }
 }
 